@@ -1,5 +1,7 @@
+use std::collections::VecDeque;
 use std::io;
 use std::path::PathBuf;
+use std::time::Duration;
 use std::time::Instant;
 
 use anyhow::Context;
@@ -12,17 +14,42 @@ use noodles::vcf::variant::record::Ids as _;
 use noodles::vcf::variant::record_buf;
 
 use rusqlite::named_params;
+use rusqlite::types::Value as SqlValue;
 use rusqlite::Connection;
 
 // TODO: Use [sqlite_zstd](https://github.com/phiresky/sqlite-zstd?tab=readme-ov-file#usage)
-// TODO: Deconstruct the INFO field into its own table (note that Values can be Arrays)
 
 pub(crate) struct Variants {
     conn: Connection,
+    // Changeset captured by the most recent `import`, written by `export_changeset`.
+    changeset: Option<Vec<u8>>,
+    backoff: Backoff,
+}
+
+// Capped exponential backoff for retrying SQLITE_BUSY/SQLITE_LOCKED under
+// concurrent writers. Deployments tune these via `Variants::new`.
+#[derive(Clone, Copy)]
+pub(crate) struct Backoff {
+    // First sleep, doubled on each retry up to `max`.
+    pub(crate) initial: Duration,
+    // Ceiling for a single sleep.
+    pub(crate) max: Duration,
+    // Total time to keep retrying before surfacing the error.
+    pub(crate) total: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(5),
+            max: Duration::from_millis(500),
+            total: Duration::from_secs(30),
+        }
+    }
 }
 
 impl Variants {
-    pub(crate) fn new(db: &PathBuf) -> Result<Self> {
+    pub(crate) fn new(db: &PathBuf, backoff: Backoff) -> Result<Self> {
         let conn = Connection::open(db)
             .with_context(|| format!("Failed to open database: {}", db.display()))?;
 
@@ -36,12 +63,23 @@ impl Variants {
                 .context("Failed to load libsqlite_zstd extension")?;
         }
 
+        register_functions(&conn)?;
+        register_collations(&conn)?;
+        load_vcf_module(&conn)?;
+
         eprintln!("Opened database: {}", db.display());
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            changeset: None,
+            backoff,
+        })
     }
 
     pub(crate) fn import(&mut self, vcf_in: &PathBuf) -> Result<()> {
-        let conn = &mut self.conn;
+        use rusqlite::session::Session;
+
+        let conn = &self.conn;
+        let backoff = self.backoff;
 
         eprintln!("Importing VCF: {}", vcf_in.display());
 
@@ -57,7 +95,6 @@ impl Variants {
             BEGIN;
 
             CREATE TABLE IF NOT EXISTS variants (
-                xrowid INTEGER PRIMARY KEY,
                 chrom TEXT,
                 pos INTEGER,
                 id TEXT,
@@ -65,14 +102,47 @@ impl Variants {
                 alt TEXT,
                 qual REAL,
                 filter TEXT,
-                info TEXT
+                -- The raw INFO string, retained as the lossless canonical copy:
+                -- `variant_info` is a derived, queryable normalization whose
+                -- reconstruction depends on the header (see `info_scalar`) and so
+                -- cannot round-trip header-less or oddly-formatted fields. It is
+                -- also the column the zstd transparent compression below targets.
+                info TEXT,
+                -- Biological identity is the key, so the session changeset keys on
+                -- it too. A variant merged from an independently-imported database
+                -- matches the same call here, or inserts cleanly when genuinely
+                -- new, rather than colliding on an unrelated surrogate rowid.
+                PRIMARY KEY (chrom, pos, ref, alt)
+            );
+
+            -- Normalized, queryable view of the INFO field: one row per key, and
+            -- one row per element for Array values (see `idx`). `value` keeps its
+            -- native SQLite affinity so `WHERE key='AF' AND value > 0.01` works;
+            -- `vtype` is the declared header type, used to reconstruct on export.
+            CREATE TABLE IF NOT EXISTS variant_info (
+                chrom TEXT,
+                pos INTEGER,
+                ref TEXT,
+                alt TEXT,
+                key TEXT,
+                idx INTEGER,
+                value,
+                vtype TEXT,
+                -- Keyed by the same biological identity as `variants` (plus
+                -- key/idx), so INFO rows travel with their parent variant in a
+                -- changeset and are never reattached to an unrelated variant's
+                -- row. Conflicts are resolved row by row on this key: omitting a
+                -- duplicate variant does not omit its INFO rows whose key/idx is
+                -- not already present for that variant.
+                PRIMARY KEY (chrom, pos, ref, alt, key, idx)
             );
 
             CREATE TABLE IF NOT EXISTS metadata (
                 header TEXT
             );
 
-            CREATE INDEX IF NOT EXISTS idx_variants_chrom_pos ON variants (chrom, pos);
+            CREATE INDEX IF NOT EXISTS idx_variants_chrom_pos ON variants (chrom COLLATE chrom_order, pos);
+            CREATE INDEX IF NOT EXISTS idx_variant_info_key ON variant_info (key);
 
             COMMIT;
 
@@ -99,14 +169,36 @@ impl Variants {
         let header = reader.read_header()?;
         store_header(&header, conn)?;
 
+        // Track row changes on `variants` and its normalized `variant_info` so
+        // importing into an existing database yields a changeset of just the
+        // inserted/changed rows, with INFO rows travelling alongside their
+        // variant (see `export_changeset`).
+        let mut session = Session::new(conn)?;
+        session.attach(Some("variants"))?;
+        session.attach(Some("variant_info"))?;
+
         let before = Instant::now();
         let mut records_processed = 0;
 
+        // A VCF may legally carry several records at the same (chrom, pos, ref,
+        // alt), and re-importing a VCF into an existing database revisits rows
+        // already present; keep the first and move on rather than aborting the
+        // import on the primary-key constraint. This keep-existing policy mirrors
+        // the conflict handler in `apply_changeset`.
         const SQL: &str = "
             INSERT INTO variants
                 (chrom, pos, id, ref, alt, qual, filter, info)
             VALUES
                 (:chrom, :pos, :id, :ref, :alt, :qual, :filter, :info)
+            ON CONFLICT DO NOTHING
+            ";
+
+        const INFO_SQL: &str = "
+            INSERT INTO variant_info
+                (chrom, pos, ref, alt, key, idx, value, vtype)
+            VALUES
+                (:chrom, :pos, :ref, :alt, :key, :idx, :value, :vtype)
+            ON CONFLICT DO NOTHING
             ";
 
         const BATCH_SIZE: usize = 10_000;
@@ -115,39 +207,137 @@ impl Variants {
         let mut done = false;
 
         while !done {
-            // Process a batch of records
-            let tx = conn.transaction()?;
+            // Process a batch of records. `unchecked_transaction` takes `&self`,
+            // so it can run while the change-tracking `Session` is attached.
+            let mut tx = conn.unchecked_transaction()?;
+            // A BUSY on COMMIT leaves the transaction open so we can retry it; the
+            // default rollback-on-drop would otherwise discard the whole batch
+            // before we regain control. We commit explicitly below either way.
+            tx.set_drop_behavior(rusqlite::DropBehavior::Ignore);
             {
                 let mut stmt = tx.prepare(SQL)?;
+                let mut info_stmt = tx.prepare(INFO_SQL)?;
 
                 for _ in 0..BATCH_SIZE {
                     if let Some(result) = records.next() {
                         let record = result?;
                         records_processed += 1;
-                        execute_record(record, &mut stmt)?;
+                        execute_record(record, &mut stmt, &mut info_stmt, &header)?;
                     } else {
                         done = true;
                         break;
                     }
                 }
 
-                let _: usize = tx
-                    .query_row("SELECT zstd_incremental_maintenance(NULL, 1)", [], |row| {
+                let _: usize = retry_busy(&backoff, || {
+                    tx.query_row("SELECT zstd_incremental_maintenance(NULL, 1)", [], |row| {
                         row.get(0)
                     })
-                    .context("Compress")?;
+                })
+                .context("Compress")?;
             }
-            tx.commit()?;
+
+            // COMMIT can fail with BUSY while the maintenance compaction overlaps
+            // readers. Because we set DropBehavior::Ignore above, a failed commit
+            // leaves the transaction active, so we retry COMMIT against it with the
+            // same backoff rather than losing the batch.
+            retry_busy(&backoff, || conn.execute_batch("COMMIT"))
+                .context("Commit batch")?;
+            drop(tx);
 
             conn.execute("VACUUM", []).context("Vacuum")?;
             eprintln!("Records processed: {records_processed}");
         }
 
         conn.execute("ANALYZE", [])?;
+
+        // Capture the changeset before the session detaches.
+        let mut changeset = Vec::new();
+        session.changeset_strm(&mut changeset)?;
+        drop(session);
+        self.changeset = Some(changeset);
+
         eprintln!("Import took {:.2?}", before.elapsed());
         Ok(())
     }
 
+    // Write the changeset captured by the most recent `import` to `path`. The
+    // result is a small binary patch of only the rows that changed, suitable for
+    // shipping to update a larger cohort database via `apply_changeset`.
+    pub(crate) fn export_changeset(&self, path: &PathBuf) -> Result<()> {
+        let changeset = self
+            .changeset
+            .as_ref()
+            .context("No changeset available; run import first")?;
+
+        std::fs::write(path, changeset)
+            .with_context(|| format!("Failed to write changeset: {}", path.display()))?;
+
+        eprintln!("Wrote changeset: {}", path.display());
+        Ok(())
+    }
+
+    // Replay a changeset produced by `export_changeset` onto this database. On a
+    // duplicate (chrom, pos, ref, alt) the existing row is kept.
+    pub(crate) fn apply_changeset(&self, path: &PathBuf) -> Result<()> {
+        use rusqlite::session::ConflictAction;
+        use rusqlite::session::ConflictType;
+
+        let mut file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open changeset: {}", path.display()))?;
+
+        self.conn.apply_strm(
+            &mut file,
+            None::<fn(&str) -> bool>,
+            |conflict, _item| match conflict {
+                // Keep the existing row rather than overwrite it.
+                ConflictType::SQLITE_CHANGESET_CONFLICT
+                | ConflictType::SQLITE_CHANGESET_CONSTRAINT => {
+                    ConflictAction::SQLITE_CHANGESET_OMIT
+                }
+                _ => ConflictAction::SQLITE_CHANGESET_REPLACE,
+            },
+        )?;
+
+        eprintln!("Applied changeset: {}", path.display());
+        Ok(())
+    }
+
+    // Produce a transactionally consistent copy of the database at `dst`, page by
+    // page, without stopping ingestion. `progress` is called with
+    // `(remaining, total)` pages after each step so callers can report progress
+    // while `import`'s maintenance/`VACUUM` keep running.
+    pub(crate) fn snapshot(&self, dst: &PathBuf, progress: impl Fn(usize, usize)) -> Result<()> {
+        use rusqlite::backup::Backup;
+        use rusqlite::backup::StepResult;
+
+        // Copy in small steps and yield between them so writers can make progress.
+        const PAGES_PER_STEP: i32 = 100;
+        const SLEEP: Duration = Duration::from_millis(250);
+
+        let mut dst_conn = Connection::open(dst)
+            .with_context(|| format!("Failed to open snapshot target: {}", dst.display()))?;
+
+        let backup = Backup::new(&self.conn, &mut dst_conn)?;
+
+        loop {
+            let state = backup.step(PAGES_PER_STEP)?;
+
+            let p = backup.progress();
+            progress(p.remaining as usize, p.pagecount as usize);
+
+            match state {
+                StepResult::Done => break,
+                StepResult::More | StepResult::Busy | StepResult::Locked => {
+                    std::thread::sleep(SLEEP)
+                }
+            }
+        }
+
+        eprintln!("Wrote snapshot: {}", dst.display());
+        Ok(())
+    }
+
     pub(crate) fn query(
         &self,
         vcf_out: &PathBuf,
@@ -156,7 +346,8 @@ impl Variants {
     ) -> Result<()> {
         let conn = &self.conn;
 
-        let sql = "SELECT chrom, pos, id, ref, alt, qual, filter, info FROM variants".to_string();
+        let sql =
+            "SELECT chrom, pos, id, ref, alt, qual, filter FROM variants".to_string();
 
         let sql = if let Some(group_by) = group_by {
             format!("{sql} GROUP BY {group_by}")
@@ -170,6 +361,9 @@ impl Variants {
             sql
         };
 
+        // Emit variants in karyotypic order (chr1..chr22, X, Y, M, then alts).
+        let sql = format!("{sql} ORDER BY chrom COLLATE chrom_order, pos");
+
         eprintln!("Exporting to VCF: {}", vcf_out.display());
         eprintln!("Query: {sql};");
 
@@ -181,7 +375,28 @@ impl Variants {
         let header = load_header(conn)?;
         writer.write_header(&header)?;
 
-        while let Some(row) = rows.next()? {
+        // SQLite reports BUSY/LOCKED from the step (`next`), so retry there with
+        // capped exponential backoff, resetting once a row comes through.
+        let backoff = self.backoff;
+        let mut delay = backoff.initial;
+        let mut elapsed = Duration::ZERO;
+
+        loop {
+            let row = match rows.next() {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(e) if is_busy(&e) && elapsed < backoff.total => {
+                    std::thread::sleep(delay);
+                    elapsed += delay;
+                    delay = (delay * 2).min(backoff.max);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            delay = backoff.initial;
+            elapsed = Duration::ZERO;
+
             let chrom: String = row.get("chrom")?;
             let pos: Option<usize> = row.get("pos")?;
             let id: Option<String> = row.get("id")?;
@@ -189,18 +404,23 @@ impl Variants {
             let alt: String = row.get("alt")?;
             let qual: Option<f32> = row.get("qual")?;
             let filter: String = row.get("filter")?;
-            let info: String = row.get("info")?;
-
-            // eprintln!("{chrom} {pos:?} {id:?} {ref_} {alt} {qual:?} {filter} {info}");
 
-            // let optional_fields = bed::record::OptionalFields::from(vec![count.to_string()]);
+            // Key for looking up this variant's INFO rows, captured before the
+            // fields are moved into the record builder below.
+            let vk = VariantKey {
+                chrom: chrom.clone(),
+                pos: pos.map(|p| p as i64),
+                ref_: ref_.clone(),
+                alt: alt.clone(),
+            };
 
             let pos = Position::try_from(pos.unwrap_or_default())?;
             let ids: record_buf::Ids = id.map(String::from).into_iter().collect();
             let alternate_bases = record_buf::AlternateBases::from(vec![alt]);
             let filters: record_buf::Filters = [filter].into_iter().collect();
 
-            let info = parse_info(&info, &header)?;
+            // Reconstruct INFO from the normalized table rather than re-parsing a string.
+            let info = load_info(conn, &vk, &header)?;
 
             let mut record = vcf::variant::RecordBuf::builder()
                 .set_reference_sequence_name(chrom)
@@ -209,7 +429,6 @@ impl Variants {
                 .set_reference_bases(ref_)
                 .set_alternate_bases(alternate_bases)
                 .set_filters(filters)
-                // .set_info("BAR=QUUX".parse()?)
                 .set_info(info)
                 .build();
 
@@ -226,63 +445,719 @@ impl Variants {
     }
 }
 
+// Retry `op` on SQLITE_BUSY/SQLITE_LOCKED with capped exponential backoff,
+// surfacing any other error immediately. Gives up once `backoff.total` elapses.
+fn retry_busy<T>(
+    backoff: &Backoff,
+    mut op: impl FnMut() -> rusqlite::Result<T>,
+) -> rusqlite::Result<T> {
+    let mut delay = backoff.initial;
+    let mut elapsed = Duration::ZERO;
+
+    loop {
+        match op() {
+            Err(e) if is_busy(&e) && elapsed < backoff.total => {
+                std::thread::sleep(delay);
+                elapsed += delay;
+                delay = (delay * 2).min(backoff.max);
+            }
+            other => return other,
+        }
+    }
+}
+
+fn is_busy(e: &rusqlite::Error) -> bool {
+    use rusqlite::ErrorCode;
+
+    matches!(
+        e,
+        rusqlite::Error::SqliteFailure(err, _)
+            if matches!(err.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+    )
+}
+
+// The biological identity of a variant — the columns that key both `variants`
+// and `variant_info`, so INFO rows stay tied to their parent across a merge.
+struct VariantKey {
+    chrom: String,
+    pos: Option<i64>,
+    ref_: String,
+    alt: String,
+}
+
 fn execute_record(
     record: vcf::Record,
     stmt: &mut rusqlite::Statement<'_>,
+    info_stmt: &mut rusqlite::Statement<'_>,
+    header: &vcf::Header,
 ) -> Result<(), anyhow::Error> {
-    let pos: Option<usize> = record.variant_start().transpose()?.map(usize::from);
     let ids = record.ids();
     let id: Option<&str> = ids.iter().next();
     let qual: Option<f32> = record.quality_score().transpose()?;
 
+    let key = VariantKey {
+        chrom: record.reference_sequence_name().to_string(),
+        pos: record
+            .variant_start()
+            .transpose()?
+            .map(|p| usize::from(p) as i64),
+        ref_: record.reference_bases().to_string(),
+        alt: record.alternate_bases().as_ref().to_string(),
+    };
+
     stmt.execute(named_params! {
-        ":chrom": record.reference_sequence_name(),
-        ":pos": pos,
+        ":chrom": key.chrom,
+        ":pos": key.pos,
         ":id": id,
-        ":ref": record.reference_bases(),
-        ":alt": record.alternate_bases().as_ref(),
+        ":ref": key.ref_,
+        ":alt": key.alt,
         ":qual": qual,
         ":filter": record.filters().as_ref(),
         ":info": record.info().as_ref(),
     })?;
 
+    store_info(&record, &key, info_stmt, header)?;
+
     Ok(())
 }
 
-fn parse_info(info: &str, header: &vcf::Header) -> Result<record_buf::Info> {
-    // TODO: There seems to be no way to set the info from a raw string
-    // (like the one we kept when reading the VCF).
-    // It seems we must parse the string and reconstruct it here :shrug:
-    //
-    // Ideas:
-    // - Use the original string parsing from the vcf reader to parse the INFO from the DB
-    // - Store the entire record in the DB and export it here.
-    //   The INFO field is the largest in any case, so this may not be a problem.
+// Expand the record's INFO field into `variant_info` rows, one per value (and
+// one per element for Array values), tagging each with its declared header type.
+fn store_info(
+    record: &vcf::Record,
+    vk: &VariantKey,
+    stmt: &mut rusqlite::Statement<'_>,
+    header: &vcf::Header,
+) -> Result<()> {
+    use record_buf::info::field::Value;
 
+    for result in record.info().iter(header) {
+        let (key, value) = result?;
+        let value: Option<Value> = value.map(|v| v.try_into()).transpose()?;
+        let vtype = info_type(key, header);
+        store_info_value(stmt, vk, key, vtype, value)?;
+    }
+
+    Ok(())
+}
+
+fn store_info_value(
+    stmt: &mut rusqlite::Statement<'_>,
+    vk: &VariantKey,
+    key: &str,
+    vtype: &str,
+    value: Option<record_buf::info::field::Value>,
+) -> Result<()> {
+    use record_buf::info::field::value::Array;
     use record_buf::info::field::Value;
 
-    // let ns = (String::from("FOO"), Some(Value::String("BAR".to_string())));
-    // let info: record_buf::Info = [ns].into_iter().collect();
+    let mut put = |idx: i64, value: SqlValue| -> Result<()> {
+        stmt.execute(named_params! {
+            ":chrom": vk.chrom,
+            ":pos": vk.pos,
+            ":ref": vk.ref_,
+            ":alt": vk.alt,
+            ":key": key,
+            ":idx": idx,
+            ":value": value,
+            ":vtype": vtype,
+        })?;
+        Ok(())
+    };
+
+    match value {
+        None | Some(Value::Flag) => put(0, SqlValue::Null)?,
+        Some(Value::Integer(n)) => put(0, SqlValue::Integer(n as i64))?,
+        Some(Value::Float(f)) => put(0, SqlValue::Real(f as f64))?,
+        Some(Value::Character(c)) => put(0, SqlValue::Text(c.to_string()))?,
+        Some(Value::String(s)) => put(0, SqlValue::Text(s))?,
+        Some(Value::Array(array)) => match array {
+            Array::Integers(values) => {
+                for (idx, value) in values.into_iter().enumerate() {
+                    let value = value.map_or(SqlValue::Null, |n| SqlValue::Integer(n as i64));
+                    put(idx as i64, value)?;
+                }
+            }
+            Array::Floats(values) => {
+                for (idx, value) in values.into_iter().enumerate() {
+                    let value = value.map_or(SqlValue::Null, |f| SqlValue::Real(f as f64));
+                    put(idx as i64, value)?;
+                }
+            }
+            Array::Characters(values) => {
+                for (idx, value) in values.into_iter().enumerate() {
+                    let value = value.map_or(SqlValue::Null, |c| SqlValue::Text(c.to_string()));
+                    put(idx as i64, value)?;
+                }
+            }
+            Array::Strings(values) => {
+                for (idx, value) in values.into_iter().enumerate() {
+                    let value = value.map_or(SqlValue::Null, SqlValue::Text);
+                    put(idx as i64, value)?;
+                }
+            }
+        },
+    }
+
+    Ok(())
+}
+
+// Reconstruct a record's INFO from its `variant_info` rows. Rows are emitted in
+// insertion order, so each key's elements arrive contiguously and in `idx` order.
+fn load_info(conn: &Connection, vk: &VariantKey, header: &vcf::Header) -> Result<record_buf::Info> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT key, value, vtype FROM variant_info \
+         WHERE chrom IS :chrom AND pos IS :pos AND ref IS :ref AND alt IS :alt \
+         ORDER BY rowid",
+    )?;
+    let mut rows = stmt.query(named_params! {
+        ":chrom": vk.chrom,
+        ":pos": vk.pos,
+        ":ref": vk.ref_,
+        ":alt": vk.alt,
+    })?;
+
+    let mut fields: Vec<(String, String, Vec<SqlValue>)> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        let key: String = row.get("key")?;
+        let value: SqlValue = row.get("value")?;
+        let vtype: String = row.get("vtype")?;
 
-    let info = vcf::record::Info::new(info);
-    let info: io::Result<Vec<_>> = info.iter(header).collect();
-    let info = info?;
-    let info: Vec<(String, Option<Value>)> = info
+        match fields.last_mut() {
+            Some((last_key, _, values)) if *last_key == key => values.push(value),
+            _ => fields.push((key, vtype, vec![value])),
+        }
+    }
+
+    let info = fields
         .into_iter()
-        .map(|(k, v)| {
-            let v: Option<Value> = v.map(|v| v.try_into().unwrap());
-            (k.to_string(), v)
+        .map(|(key, vtype, values)| {
+            let scalar = info_scalar(&key, header, values.len());
+            (key, build_info_value(&vtype, values, scalar))
         })
         .collect();
-    let info: record_buf::Info = info.into_iter().collect();
 
-    // eprintln!("{info:#?}");
-    // std::process::exit(0);
+    Ok(info)
+}
 
-    // let info: std::io::Result<Vec<_>> = info.iter(&header).collect();
-    // let info = record_buf::Info::from(info); // = info.iter(&header).collect();
+// Whether a field reconstructs as a scalar rather than an Array. The header's
+// declared `Number` is authoritative (`Number=1` is scalar, `A`/`R`/`G`/`.` and
+// any other fixed count are arrays); a single stored element no longer implies a
+// scalar. Falls back to the stored element count for keys absent from the header.
+fn info_scalar(key: &str, header: &vcf::Header, stored_len: usize) -> bool {
+    use vcf::header::record::value::map::Number;
+
+    match header.infos().get(key).map(|info| info.number()) {
+        Some(Number::Count(1)) => true,
+        Some(_) => false,
+        None => stored_len == 1,
+    }
+}
 
-    Ok(info)
+fn build_info_value(
+    vtype: &str,
+    values: Vec<SqlValue>,
+    scalar: bool,
+) -> Option<record_buf::info::field::Value> {
+    use record_buf::info::field::value::Array;
+    use record_buf::info::field::Value;
+
+    match vtype {
+        "Flag" => Some(Value::Flag),
+        "Integer" if scalar => as_i32(&values[0]).map(Value::Integer),
+        "Integer" => Some(Value::Array(Array::Integers(
+            values.iter().map(as_i32).collect(),
+        ))),
+        "Float" if scalar => as_f32(&values[0]).map(Value::Float),
+        "Float" => Some(Value::Array(Array::Floats(
+            values.iter().map(as_f32).collect(),
+        ))),
+        "Character" if scalar => as_char(&values[0]).map(Value::Character),
+        "Character" => Some(Value::Array(Array::Characters(
+            values.iter().map(as_char).collect(),
+        ))),
+        _ if scalar => as_string(&values[0]).map(Value::String),
+        _ => Some(Value::Array(Array::Strings(
+            values.into_iter().map(|v| as_string(&v)).collect(),
+        ))),
+    }
+}
+
+fn as_i32(value: &SqlValue) -> Option<i32> {
+    match value {
+        SqlValue::Integer(n) => Some(*n as i32),
+        _ => None,
+    }
+}
+
+fn as_f32(value: &SqlValue) -> Option<f32> {
+    match value {
+        SqlValue::Real(f) => Some(*f as f32),
+        SqlValue::Integer(n) => Some(*n as f32),
+        _ => None,
+    }
+}
+
+fn as_char(value: &SqlValue) -> Option<char> {
+    match value {
+        SqlValue::Text(s) => s.chars().next(),
+        _ => None,
+    }
+}
+
+fn as_string(value: &SqlValue) -> Option<String> {
+    match value {
+        SqlValue::Text(s) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+// The INFO type declared in the header, used both to store and to reconstruct.
+fn info_type(key: &str, header: &vcf::Header) -> &'static str {
+    use vcf::header::record::value::map::info::Type;
+
+    match header.infos().get(key).map(|info| info.ty()) {
+        Some(Type::Integer) => "Integer",
+        Some(Type::Float) => "Float",
+        Some(Type::Flag) => "Flag",
+        Some(Type::Character) => "Character",
+        Some(Type::String) | None => "String",
+    }
+}
+
+// Register the genomic-interval UDFs used by `query`. Both work on the half-open
+// interval a variant spans, `[pos, pos + len(ref))`, against a query `region`.
+fn register_functions(conn: &Connection) -> Result<()> {
+    use rusqlite::functions::FunctionFlags;
+
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+
+    // overlaps(chrom, pos, ref, region): true iff the variant's span intersects region.
+    conn.create_scalar_function("overlaps", 4, flags, |ctx| {
+        let chrom = ctx.get::<String>(0)?;
+        let pos = ctx.get::<i64>(1)?;
+        let ref_ = ctx.get::<String>(2)?;
+        let region = ctx.get::<String>(3)?;
+
+        let (r_chrom, r_start, r_end) = parse_region(&region)
+            .ok_or_else(|| rusqlite::Error::UserFunctionError(bad_region(&region)))?;
+
+        let end = pos + ref_.len() as i64;
+        Ok(chrom == r_chrom && pos < r_end && r_start < end)
+    })?;
+
+    // in_region(chrom, pos, region): true iff the variant's start falls in region.
+    conn.create_scalar_function("in_region", 3, flags, |ctx| {
+        let chrom = ctx.get::<String>(0)?;
+        let pos = ctx.get::<i64>(1)?;
+        let region = ctx.get::<String>(2)?;
+
+        let (r_chrom, r_start, r_end) = parse_region(&region)
+            .ok_or_else(|| rusqlite::Error::UserFunctionError(bad_region(&region)))?;
+
+        Ok(chrom == r_chrom && r_start <= pos && pos < r_end)
+    })?;
+
+    Ok(())
+}
+
+// Register custom collations used on the `chrom` column and in `query` ordering.
+fn register_collations(conn: &Connection) -> Result<()> {
+    conn.create_collation("chrom_order", |a, b| chrom_key(a).cmp(&chrom_key(b)))?;
+    Ok(())
+}
+
+// Sort key putting contigs in karyotypic order: numeric contigs first (ordered
+// numerically), then X, Y, M/MT, then any remaining alt/scaffold contigs
+// lexically. An optional `chr`/`Chr` prefix is ignored.
+fn chrom_key(name: &str) -> (u8, i64, String) {
+    let bare = name
+        .strip_prefix("chr")
+        .or_else(|| name.strip_prefix("Chr"))
+        .unwrap_or(name);
+
+    if let Ok(n) = bare.parse::<i64>() {
+        return (0, n, String::new());
+    }
+
+    match bare {
+        "X" => (1, 0, String::new()),
+        "Y" => (1, 1, String::new()),
+        "M" | "MT" => (1, 2, String::new()),
+        _ => (2, 0, bare.to_string()),
+    }
+}
+
+// Parse a region string like "chr1:1000-2000" into `(chrom, start, end)`, where
+// `[start, end)` is half-open.
+fn parse_region(region: &str) -> Option<(String, i64, i64)> {
+    let (chrom, range) = region.rsplit_once(':')?;
+    let (start, end) = range.split_once('-')?;
+    Some((chrom.to_string(), start.parse().ok()?, end.parse().ok()?))
+}
+
+fn bad_region(region: &str) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+    format!("Invalid region: {region}").into()
+}
+
+// Read-only virtual table exposing a VCF file as eight columns mirroring the
+// `variants` table (chrom, pos, id, ref, alt, qual, filter) plus an `info` TEXT
+// column. Lets callers query a VCF without importing it:
+//   CREATE VIRTUAL TABLE v USING vcf(filename='in.vcf.gz');
+//   SELECT chrom, pos, id FROM v WHERE chrom='chr1';
+fn load_vcf_module(conn: &Connection) -> Result<()> {
+    use rusqlite::vtab::read_only_module;
+
+    let aux: Option<()> = None;
+    conn.create_module("vcf", read_only_module::<VcfTab>(), aux)?;
+    Ok(())
+}
+
+const VCF_COLUMNS: [&str; 8] = [
+    "chrom", "pos", "id", "ref", "alt", "qual", "filter", "info",
+];
+
+#[repr(C)]
+struct VcfTab {
+    base: rusqlite::vtab::sqlite3_vtab,
+    filename: String,
+}
+
+unsafe impl<'vtab> rusqlite::vtab::VTab<'vtab> for VcfTab {
+    type Aux = ();
+    type Cursor = VcfTabCursor;
+
+    fn connect(
+        _db: &mut rusqlite::vtab::VTabConnection,
+        _aux: Option<&()>,
+        args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        let filename = parse_filename(args).ok_or_else(|| {
+            rusqlite::Error::ModuleError("vcf: missing filename= parameter".to_string())
+        })?;
+
+        let schema = "CREATE TABLE x(chrom TEXT, pos INTEGER, id TEXT, ref TEXT, \
+             alt TEXT, qual REAL, filter TEXT, info TEXT)"
+            .to_string();
+
+        let vtab = VcfTab {
+            base: rusqlite::vtab::sqlite3_vtab::default(),
+            filename,
+        };
+        Ok((schema, vtab))
+    }
+
+    // Push down equality on `chrom` and equality/range on `pos`; the recognized
+    // constraints are encoded in idx_str and handed to the cursor as filter args.
+    // When a `chrom` equality is pushed and the file has a tabix/CSI index, the
+    // cursor seeks to the region instead of scanning (see `VcfTabCursor::filter`).
+    fn best_index(&self, info: &mut rusqlite::vtab::IndexInfo) -> rusqlite::Result<()> {
+        use rusqlite::vtab::IndexConstraintOp::*;
+
+        let recognized: Vec<(usize, &str)> = info
+            .constraints()
+            .enumerate()
+            .filter(|(_, c)| c.is_usable())
+            .filter_map(|(i, c)| {
+                let token = match (c.column(), c.operator()) {
+                    (0, SQLITE_INDEX_CONSTRAINT_EQ) => "chrom=",
+                    (1, SQLITE_INDEX_CONSTRAINT_EQ) => "pos=",
+                    (1, SQLITE_INDEX_CONSTRAINT_GT) => "pos>",
+                    (1, SQLITE_INDEX_CONSTRAINT_GE) => "pos>=",
+                    (1, SQLITE_INDEX_CONSTRAINT_LT) => "pos<",
+                    (1, SQLITE_INDEX_CONSTRAINT_LE) => "pos<=",
+                    _ => return None,
+                };
+                Some((i, token))
+            })
+            .collect();
+
+        let mut tokens = Vec::with_capacity(recognized.len());
+        for (argv, (i, token)) in recognized.into_iter().enumerate() {
+            let mut usage = info.constraint_usage(i);
+            usage.set_argv_index(argv as i32 + 1);
+            usage.set_omit(true);
+            tokens.push(token);
+        }
+
+        info.set_idx_str(&tokens.join(","));
+        // A `chrom` equality lets the cursor seek via the index when one exists,
+        // cutting the work to the requested region; without it we scan the file.
+        let can_seek = tokens.contains(&"chrom=");
+        info.set_estimated_cost(if can_seek { 1000.0 } else { 1_000_000.0 });
+        Ok(())
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<VcfTabCursor> {
+        Ok(VcfTabCursor::new(self.filename.clone()))
+    }
+}
+
+impl rusqlite::vtab::CreateVTab<'_> for VcfTab {
+    const KIND: rusqlite::vtab::VTabKind = rusqlite::vtab::VTabKind::Default;
+}
+
+// Lower/upper bounds on `pos`, inclusive, derived from the pushed-down constraints.
+#[derive(Default)]
+struct PosBounds {
+    lo: Option<i64>,
+    hi: Option<i64>,
+}
+
+struct VcfTabCursor {
+    base: rusqlite::vtab::sqlite3_vtab_cursor,
+    filename: String,
+    // Scan path: a reader streamed from the start of the file.
+    reader: Option<vcf::io::Reader<Box<dyn io::BufRead>>>,
+    // Seek path: rows pre-fetched from the index-backed region query, drained in
+    // order. `Some` iff `filter` took the indexed fast path.
+    buffered: Option<VecDeque<VcfRow>>,
+    record: vcf::Record,
+    chrom_filter: Option<String>,
+    pos_bounds: PosBounds,
+    // Decoded current row, owned so `column` is a simple lookup.
+    row: Option<VcfRow>,
+    rowid: i64,
+}
+
+struct VcfRow {
+    chrom: String,
+    pos: Option<i64>,
+    id: Option<String>,
+    ref_: String,
+    alt: String,
+    qual: Option<f64>,
+    filter: String,
+    info: String,
+}
+
+impl VcfTabCursor {
+    fn new(filename: String) -> Self {
+        VcfTabCursor {
+            base: rusqlite::vtab::sqlite3_vtab_cursor::default(),
+            filename,
+            reader: None,
+            buffered: None,
+            record: vcf::Record::default(),
+            chrom_filter: None,
+            pos_bounds: PosBounds::default(),
+            row: None,
+            rowid: 0,
+        }
+    }
+
+    // (Re)open the underlying reader from the start, skipping the header. Used for
+    // the scan path; `advance`/`matches` discard non-matching rows.
+    fn reopen(&mut self) -> Result<()> {
+        let path = PathBuf::from(&self.filename);
+        let is_gz = self.filename.ends_with(".gz");
+
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("Failed to open VCF file: {}", path.display()))?;
+        let inner: Box<dyn io::BufRead> = if is_gz {
+            Box::new(noodles::bgzf::io::Reader::new(file))
+        } else {
+            Box::new(io::BufReader::new(file))
+        };
+
+        let mut reader = vcf::io::Reader::new(inner);
+        reader.read_header()?;
+        self.reader = Some(reader);
+        Ok(())
+    }
+
+    // If a `chrom` filter is set and the file has a tabix/CSI index, seek to the
+    // requested region and buffer its records rather than scanning the whole file.
+    // Returns `None` (leaving the caller to `reopen` and scan) when no index is
+    // present or no `chrom` was pushed down. `pos` bounds, when both sides are
+    // known, narrow the sought interval; `matches` still refines the result.
+    fn indexed_query(&self) -> Result<Option<VecDeque<VcfRow>>> {
+        let Some(chrom) = &self.chrom_filter else {
+            return Ok(None);
+        };
+
+        let has_index = ["tbi", "csi"]
+            .iter()
+            .any(|ext| PathBuf::from(format!("{}.{ext}", self.filename)).exists());
+        if !has_index {
+            return Ok(None);
+        }
+
+        let region = match (self.pos_bounds.lo, self.pos_bounds.hi) {
+            (Some(lo), Some(hi)) => format!("{chrom}:{lo}-{hi}"),
+            _ => chrom.clone(),
+        };
+        let region: noodles::core::Region = region
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid region: {region}"))?;
+
+        let path = PathBuf::from(&self.filename);
+        let mut reader = vcf::io::indexed_reader::Builder::default()
+            .build_from_path(&path)
+            .with_context(|| format!("Failed to open indexed VCF file: {}", path.display()))?;
+        let header = reader.read_header()?;
+
+        let mut rows = VecDeque::new();
+        for result in reader.query(&header, &region)? {
+            rows.push_back(decode_row(&result?)?);
+        }
+        Ok(Some(rows))
+    }
+
+    fn matches(&self, row: &VcfRow) -> bool {
+        if let Some(chrom) = &self.chrom_filter {
+            if row.chrom != *chrom {
+                return false;
+            }
+        }
+        if let Some(pos) = row.pos {
+            if self.pos_bounds.lo.is_some_and(|lo| pos < lo) {
+                return false;
+            }
+            if self.pos_bounds.hi.is_some_and(|hi| pos > hi) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Advance to the next record satisfying the pushed-down constraints, or EOF.
+    // Rows come from the buffered region query when `filter` seeked, otherwise
+    // from the streaming reader; either way `matches` refines the result.
+    fn advance(&mut self) -> Result<()> {
+        loop {
+            let row = if let Some(buffered) = self.buffered.as_mut() {
+                match buffered.pop_front() {
+                    Some(row) => row,
+                    None => {
+                        self.row = None;
+                        return Ok(());
+                    }
+                }
+            } else {
+                let reader = self.reader.as_mut().expect("reader opened in filter");
+                if reader.read_record(&mut self.record)? == 0 {
+                    self.row = None;
+                    return Ok(());
+                }
+                decode_row(&self.record)?
+            };
+
+            if self.matches(&row) {
+                self.rowid += 1;
+                self.row = Some(row);
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Decode a VCF record into the owned row the cursor hands back to SQLite.
+fn decode_row(record: &vcf::Record) -> Result<VcfRow> {
+    let pos: Option<i64> = record
+        .variant_start()
+        .transpose()?
+        .map(|p| usize::from(p) as i64);
+    let id = record.ids().iter().next().map(str::to_string);
+    let qual: Option<f64> = record.quality_score().transpose()?.map(f64::from);
+
+    Ok(VcfRow {
+        chrom: record.reference_sequence_name().to_string(),
+        pos,
+        id,
+        ref_: record.reference_bases().to_string(),
+        alt: record.alternate_bases().as_ref().to_string(),
+        qual,
+        filter: record.filters().as_ref().to_string(),
+        info: record.info().as_ref().to_string(),
+    })
+}
+
+unsafe impl rusqlite::vtab::VTabCursor for VcfTabCursor {
+    fn filter(
+        &mut self,
+        _idx_num: std::os::raw::c_int,
+        idx_str: Option<&str>,
+        args: &rusqlite::vtab::Values<'_>,
+    ) -> rusqlite::Result<()> {
+        self.chrom_filter = None;
+        self.pos_bounds = PosBounds::default();
+
+        // Decode the constraints best_index recognized, in argv order.
+        if let Some(idx_str) = idx_str.filter(|s| !s.is_empty()) {
+            for (token, value) in idx_str.split(',').zip(args.iter()) {
+                match token {
+                    "chrom=" => self.chrom_filter = Some(value.get::<String>()?),
+                    "pos=" => {
+                        let v = value.get::<i64>()?;
+                        self.pos_bounds.lo = Some(v);
+                        self.pos_bounds.hi = Some(v);
+                    }
+                    "pos>" => self.pos_bounds.lo = Some(value.get::<i64>()? + 1),
+                    "pos>=" => self.pos_bounds.lo = Some(value.get::<i64>()?),
+                    "pos<" => self.pos_bounds.hi = Some(value.get::<i64>()? - 1),
+                    "pos<=" => self.pos_bounds.hi = Some(value.get::<i64>()?),
+                    _ => {}
+                }
+            }
+        }
+
+        self.rowid = 0;
+
+        // Prefer an index-backed seek; fall back to a streaming scan when the
+        // file has no index or no `chrom` was pushed down.
+        (|| -> Result<()> {
+            self.reader = None;
+            self.buffered = self.indexed_query()?;
+            if self.buffered.is_none() {
+                self.reopen()?;
+            }
+            self.advance()
+        })()
+        .map_err(|e| rusqlite::Error::ModuleError(e.to_string()))
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        self.advance()
+            .map_err(|e| rusqlite::Error::ModuleError(e.to_string()))
+    }
+
+    fn eof(&self) -> bool {
+        self.row.is_none()
+    }
+
+    fn column(&self, ctx: &mut rusqlite::vtab::Context, i: std::os::raw::c_int) -> rusqlite::Result<()> {
+        let row = self.row.as_ref().expect("column called on a valid row");
+        match VCF_COLUMNS.get(i as usize) {
+            Some(&"chrom") => ctx.set_result(&row.chrom),
+            Some(&"pos") => ctx.set_result(&row.pos),
+            Some(&"id") => ctx.set_result(&row.id),
+            Some(&"ref") => ctx.set_result(&row.ref_),
+            Some(&"alt") => ctx.set_result(&row.alt),
+            Some(&"qual") => ctx.set_result(&row.qual),
+            Some(&"filter") => ctx.set_result(&row.filter),
+            Some(&"info") => ctx.set_result(&row.info),
+            _ => Ok(()),
+        }
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        Ok(self.rowid)
+    }
+}
+
+// Pull the `filename='...'` parameter out of the module arguments.
+fn parse_filename(args: &[&[u8]]) -> Option<String> {
+    // args[0..3] are the module, database and table names; parameters follow.
+    for arg in args.iter().skip(3) {
+        let arg = std::str::from_utf8(arg).ok()?.trim();
+        if let Some(value) = arg.strip_prefix("filename=") {
+            let value = value.trim().trim_matches(['\'', '"']);
+            return Some(value.to_string());
+        }
+    }
+    None
 }
 
 fn store_header(header: &vcf::Header, conn: &Connection) -> Result<()> {